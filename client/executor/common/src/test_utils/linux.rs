@@ -16,18 +16,38 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-//! Implementation of Linux specific tests and/or helper functions.
+//! Linux specific helper functions, based on `/proc/self/smaps`.
 
-use crate::wasm_runtime::WasmInstance;
 use std::{
 	ops::Range,
 	collections::BTreeMap,
+	io::{BufRead, BufReader, Read, Seek, SeekFrom},
 };
 
-/// Returns how much bytes of the instance's memory is currently resident (backed by phys mem)
-pub fn instance_resident_bytes(instance: &dyn WasmInstance) -> usize {
-	let base_addr = instance.linear_memory_range().unwrap().start;
-	Smaps::new().get_rss(base_addr).expect("failed to get rss")
+/// Returns how many bytes of the given address range are currently resident (backed by phys
+/// mem), using the mapping that `addr` falls into.
+///
+/// `len` is currently unused: [`Smaps`] only knows how to report the residency of a whole
+/// mapping, so the range is assumed to coincide with one.
+pub(super) fn resident_bytes_for_range(addr: usize, _len: usize) -> Option<usize> {
+	// smaps reports sizes in kB.
+	Smaps::new().get_rss(addr).map(|kb| kb * 1024)
+}
+
+/// A breakdown of a memory mapping's footprint, in kB, as reported by `/proc/self/smaps`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryBreakdown {
+	/// Resident set size: all pages currently resident, shared or not.
+	pub rss: usize,
+	/// Proportional set size: the mapping's resident pages, with shared pages divided by the
+	/// number of processes mapping them.
+	pub pss: usize,
+	/// How much of the mapping is currently swapped out.
+	pub swap: usize,
+	/// Dirty pages that are private to this mapping.
+	pub private_dirty: usize,
+	/// Clean pages that are shared with other mappings.
+	pub shared_clean: usize,
 }
 
 /// An interface to the /proc/self/smaps
@@ -40,38 +60,40 @@ pub struct Smaps(Vec<(Range<usize>, BTreeMap<String, usize>)>);
 impl Smaps {
 	/// Create a in-memory representation of the calling processe's memory map.
 	pub fn new() -> Self {
-		let regex_start = regex::RegexBuilder::new("^([0-9a-f]+)-([0-9a-f]+)")
-			.multi_line(true)
-			.build()
-			.unwrap();
-		let regex_kv = regex::RegexBuilder::new(r#"^([^:]+):\s*(\d+) kB"#)
-			.multi_line(true)
-			.build()
-			.unwrap();
-		let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
-		let boundaries: Vec<_> = regex_start
-			.find_iter(&smaps)
-			.map(|matched| matched.start())
-			.chain(std::iter::once(smaps.len()))
-			.collect();
+		let file = std::fs::File::open("/proc/self/smaps").unwrap();
+		Self::parse(file)
+	}
 
+	/// Parse the smaps format out of `source`, line by line.
+	///
+	/// Every mapping starts with a header line of the form `<start>-<end> perms offset dev
+	/// inode pathname`, followed by a number of `Key: <value> kB` lines that describe it. The
+	/// last mapping has no following header, so it is flushed once the input is exhausted.
+	fn parse(source: impl std::io::Read) -> Self {
 		let mut output = Vec::new();
-		for window in boundaries.windows(2) {
-			let chunk = &smaps[window[0]..window[1]];
-			let caps = regex_start.captures(chunk).unwrap();
-			let start = usize::from_str_radix(caps.get(1).unwrap().as_str(), 16).unwrap();
-			let end = usize::from_str_radix(caps.get(2).unwrap().as_str(), 16).unwrap();
-
-			let values = regex_kv
-				.captures_iter(chunk)
-				.map(|cap| {
-					let key = cap.get(1).unwrap().as_str().to_owned();
-					let value = cap.get(2).unwrap().as_str().parse().unwrap();
-					(key, value)
-				})
-				.collect();
-
-			output.push((start..end, values));
+		let mut current: Option<(Range<usize>, BTreeMap<String, usize>)> = None;
+
+		for line in BufReader::new(source).lines() {
+			let line = match line {
+				Ok(line) => line,
+				Err(_) => break,
+			};
+
+			if let Some((start, end)) = parse_header(&line) {
+				if let Some(mapping) = current.take() {
+					output.push(mapping);
+				}
+				current = Some((start..end, BTreeMap::new()));
+				continue
+			}
+
+			if let (Some((_, values)), Some((key, value))) = (&mut current, parse_kv(&line)) {
+				values.insert(key, value);
+			}
+		}
+
+		if let Some(mapping) = current.take() {
+			output.push(mapping);
 		}
 
 		Self(output)
@@ -80,7 +102,54 @@ impl Smaps {
 	/// Returns how much memory is currently resident in the memory mapping that is
 	/// associated with the specified address.
 	pub fn get_rss(&self, addr: usize) -> Option<usize> {
-		self.get_map(addr).get("Rss").cloned()
+		self.get_field(addr, "Rss")
+	}
+
+	/// Returns the proportional set size (PSS) of the memory mapping that is associated with
+	/// the specified address, i.e. the mapping's resident size divided proportionally among
+	/// all the processes that share it.
+	///
+	/// This is generally a better measure of a wasm instance's footprint than [`Self::get_rss`]:
+	/// a large part of a runtime's resident memory is shared, read-only code pages, and RSS
+	/// over-counts them whenever several instances map the same module.
+	pub fn get_pss(&self, addr: usize) -> Option<usize> {
+		self.get_field(addr, "Pss")
+	}
+
+	/// Returns how much of the memory mapping that is associated with the specified address is
+	/// currently swapped out.
+	pub fn get_swap(&self, addr: usize) -> Option<usize> {
+		self.get_field(addr, "Swap")
+	}
+
+	/// Returns the amount of private (i.e. not shared with any other process) dirty memory in
+	/// the mapping that is associated with the specified address.
+	pub fn get_private_dirty(&self, addr: usize) -> Option<usize> {
+		self.get_field(addr, "Private_Dirty")
+	}
+
+	/// Returns the amount of shared clean memory in the mapping that is associated with the
+	/// specified address.
+	pub fn get_shared_clean(&self, addr: usize) -> Option<usize> {
+		self.get_field(addr, "Shared_Clean")
+	}
+
+	/// Returns the value of an arbitrary smaps field (e.g. `"Referenced"`, `"Anonymous"`) for
+	/// the mapping that is associated with the specified address.
+	pub fn get_field(&self, addr: usize, field: &str) -> Option<usize> {
+		self.get_map(addr).get(field).cloned()
+	}
+
+	/// Returns a [`MemoryBreakdown`] of the mapping that is associated with the specified
+	/// address.
+	pub fn get_memory_breakdown(&self, addr: usize) -> MemoryBreakdown {
+		MemoryBreakdown {
+			rss: self.get_rss(addr).unwrap_or(0),
+			pss: self.get_pss(addr).unwrap_or(0),
+			swap: self.get_swap(addr).unwrap_or(0),
+			private_dirty: self.get_private_dirty(addr).unwrap_or(0),
+			shared_clean: self.get_shared_clean(addr).unwrap_or(0),
+		}
 	}
 
 	/// Get the mapping at the specified address.
@@ -91,4 +160,163 @@ impl Smaps {
 			.unwrap()
 			.1
 	}
-}
\ No newline at end of file
+}
+
+/// A cheap summary of the whole process's memory footprint.
+///
+/// Unlike [`Smaps`], which has to parse every individual mapping, this reads
+/// `/proc/self/smaps_rollup`, a synthetic entry the kernel maintains with the fields of every
+/// mapping already pre-summed. Useful for memory-growth tests that only care about the
+/// process-wide total before and after a batch of work, not any particular mapping.
+pub struct SmapsRollup(BTreeMap<String, usize>);
+
+impl SmapsRollup {
+	/// Create a summary of the whole process's memory footprint.
+	///
+	/// Falls back to summing every mapping out of [`Smaps`] on kernels older than 4.14, which
+	/// don't expose `/proc/self/smaps_rollup`.
+	pub fn new() -> Self {
+		match std::fs::File::open("/proc/self/smaps_rollup") {
+			Ok(file) => {
+				let Smaps(mappings) = Smaps::parse(file);
+				Self(mappings.into_iter().next().map(|(_, values)| values).unwrap_or_default())
+			},
+			Err(_) => Self(Self::sum_smaps()),
+		}
+	}
+
+	fn sum_smaps() -> BTreeMap<String, usize> {
+		let mut totals = BTreeMap::new();
+		for (_, values) in Smaps::new().0 {
+			for (key, value) in values {
+				*totals.entry(key).or_insert(0) += value;
+			}
+		}
+		totals
+	}
+
+	/// Returns the total resident set size (RSS) of the process.
+	pub fn total_rss(&self) -> usize {
+		self.0.get("Rss").cloned().unwrap_or(0)
+	}
+
+	/// Returns the total proportional set size (PSS) of the process.
+	pub fn total_pss(&self) -> usize {
+		self.0.get("Pss").cloned().unwrap_or(0)
+	}
+
+	/// Returns how much of the process's memory is currently swapped out.
+	pub fn total_swap(&self) -> usize {
+		self.0.get("Swap").cloned().unwrap_or(0)
+	}
+}
+
+/// Parse a smaps header line, e.g. `7f2abcd00000-7f2abcd01000 r--p 00000000 00:00 0`, into its
+/// `start` and `end` addresses. The `perms offset dev inode pathname` fields that follow are
+/// not needed here and are left unparsed.
+fn parse_header(line: &str) -> Option<(usize, usize)> {
+	let (start, rest) = line.split_once('-')?;
+	let end = rest.split_whitespace().next()?;
+
+	let start = usize::from_str_radix(start, 16).ok()?;
+	let end = usize::from_str_radix(end, 16).ok()?;
+
+	Some((start, end))
+}
+
+/// Parse a `Key: <value> kB` line into its key and value. Lines whose value doesn't carry the
+/// `kB` suffix (e.g. `VmFlags`) are not relevant to our accounting and are ignored.
+fn parse_kv(line: &str) -> Option<(String, usize)> {
+	let (key, value) = line.split_once(':')?;
+	let value = value.trim().strip_suffix("kB")?.trim();
+
+	Some((key.trim().to_owned(), value.parse().ok()?))
+}
+
+const PAGEMAP_PRESENT: u64 = 1 << 63;
+const PAGEMAP_SOFT_DIRTY: u64 = 1 << 55;
+
+/// Resets the soft-dirty bit on every page of the process and begins tracking it anew, by
+/// writing "4" to `/proc/self/clear_refs`.
+///
+/// See the kernel docs on [soft-dirty PTEs][soft-dirty].
+///
+/// [soft-dirty]: https://www.kernel.org/doc/html/latest/admin-guide/mm/soft-dirty.html
+fn reset_soft_dirty() -> std::io::Result<()> {
+	std::fs::write("/proc/self/clear_refs", b"4\n")
+}
+
+/// Returns how many bytes of `[base, base + len)` lie in pages that have their soft-dirty bit
+/// set in `/proc/self/pagemap`, i.e. have been written to since the last [`reset_soft_dirty`]
+/// call. The result is the number of such pages times the page size.
+///
+/// `base` must be page-aligned, which holds for a wasm instance's linear memory base
+/// (`linear_memory_range().start`). Returns `None` if pagemap can't be opened or read. A kernel
+/// without `CONFIG_MEM_SOFT_DIRTY` is not detected here (see [`soft_dirty_is_supported`]).
+fn count_soft_dirty_pages(base: usize, len: usize) -> Option<usize> {
+	let page_size = page_size::get();
+	assert_eq!(base % page_size, 0, "base address must be page-aligned");
+
+	let mut pagemap = std::fs::File::open("/proc/self/pagemap").ok()?;
+	let page_count = (len + page_size - 1) / page_size;
+
+	let mut touched = 0;
+	let mut entry = [0u8; 8];
+	for page_index in 0..page_count {
+		let offset = (base / page_size + page_index) as u64 * 8;
+		pagemap.seek(SeekFrom::Start(offset)).ok()?;
+
+		match pagemap.read_exact(&mut entry) {
+			Ok(()) => {},
+			// The mapping may be shorter than `len` rounded up to a page; stop at EOF.
+			Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+			Err(_) => return None,
+		}
+
+		let entry = u64::from_ne_bytes(entry);
+		if entry & PAGEMAP_PRESENT != 0 && entry & PAGEMAP_SOFT_DIRTY != 0 {
+			touched += 1;
+		}
+	}
+
+	Some(touched * page_size)
+}
+
+/// Checks whether the running kernel actually tracks soft-dirty bits.
+///
+/// `/proc/self/clear_refs` accepts the `"4"` (soft-dirty) command and `/proc/self/pagemap`
+/// stays readable even when the kernel was built without `CONFIG_MEM_SOFT_DIRTY`; in that case
+/// bit 55 is simply never set, which is indistinguishable from "nothing was touched" unless we
+/// check for it. So: reset, write to a canary byte ourselves, and confirm its page comes back
+/// dirty before trusting the mechanism for the caller's measurement.
+fn soft_dirty_is_supported() -> bool {
+	if reset_soft_dirty().is_err() {
+		return false
+	}
+
+	let mut canary = 0u8;
+	// Volatile so the write can't be optimised away.
+	unsafe { std::ptr::write_volatile(&mut canary, 1) };
+
+	let page_size = page_size::get();
+	let canary_addr = &canary as *const u8 as usize;
+	let page_base = canary_addr - canary_addr % page_size;
+
+	matches!(count_soft_dirty_pages(page_base, page_size), Some(touched) if touched > 0)
+}
+
+/// Runs `f`, then returns how many bytes of `[base, base + len)` it touched, by resetting the
+/// soft-dirty bits beforehand and counting the ones that are set afterwards.
+///
+/// Returns `None` if the kernel doesn't actually track soft-dirty bits (e.g. it lacks
+/// `CONFIG_MEM_SOFT_DIRTY`), rather than the misleading `Some(0)` a kernel without the feature
+/// would otherwise produce.
+pub(super) fn working_set_bytes_for_range(base: usize, len: usize, f: impl FnOnce()) -> Option<usize> {
+	if !soft_dirty_is_supported() {
+		return None
+	}
+
+	reset_soft_dirty().ok()?;
+	f();
+	count_soft_dirty_pages(base, len)
+}
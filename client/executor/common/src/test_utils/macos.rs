@@ -0,0 +1,78 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! macOS specific helper functions, based on `mach_vm_region`.
+
+use mach::{
+	kern_return::KERN_SUCCESS,
+	traps::mach_task_self,
+	vm::mach_vm_region,
+	vm_region::{vm_region_extended_info_data_t, VM_REGION_EXTENDED_INFO},
+	vm_types::{mach_vm_address_t, mach_vm_size_t},
+};
+use std::mem;
+
+/// Returns how many bytes of the given address range are currently resident (backed by phys
+/// mem), by walking the regions `mach_vm_region` reports starting at `addr`. For every region
+/// that overlaps `[addr, addr + len)`, its `pages_resident` is scaled by the share of the
+/// region's pages that fall inside the range, so the result is an estimate for regions that
+/// only partly overlap.
+pub(super) fn resident_bytes_for_range(addr: usize, len: usize) -> Option<usize> {
+	let page_size = page_size::get();
+	let range_end = addr.checked_add(len)?;
+
+	let mut resident_bytes = 0;
+	let mut cursor = addr as mach_vm_address_t;
+
+	while (cursor as usize) < range_end {
+		let mut region_addr = cursor;
+		let mut region_size: mach_vm_size_t = 0;
+		let mut info: vm_region_extended_info_data_t = unsafe { mem::zeroed() };
+		let mut info_count = (mem::size_of::<vm_region_extended_info_data_t>() / mem::size_of::<u32>()) as u32;
+		let mut object_name = 0;
+
+		let kr = unsafe {
+			mach_vm_region(
+				mach_task_self(),
+				&mut region_addr,
+				&mut region_size,
+				VM_REGION_EXTENDED_INFO,
+				&mut info as *mut _ as *mut _,
+				&mut info_count,
+				&mut object_name,
+			)
+		};
+		if kr != KERN_SUCCESS {
+			break;
+		}
+
+		let region_range = region_addr as usize..(region_addr as usize + region_size as usize);
+		let overlap_start = region_range.start.max(addr);
+		let overlap_end = region_range.end.min(range_end);
+		if overlap_end > overlap_start {
+			let overlap_pages = (overlap_end - overlap_start + page_size - 1) / page_size;
+			let region_pages = (region_size as usize + page_size - 1) / page_size;
+			let resident_ratio = info.pages_resident as usize * overlap_pages / region_pages.max(1);
+			resident_bytes += resident_ratio * page_size;
+		}
+
+		cursor = region_addr + region_size;
+	}
+
+	Some(resident_bytes)
+}
@@ -0,0 +1,66 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Implementation of platform specific tests and/or helper functions.
+
+use crate::wasm_runtime::WasmInstance;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "windows")]
+mod windows;
+
+#[cfg(target_os = "linux")]
+use linux::resident_bytes_for_range;
+#[cfg(target_os = "macos")]
+use macos::resident_bytes_for_range;
+#[cfg(target_os = "windows")]
+use windows::resident_bytes_for_range;
+
+#[cfg(target_os = "linux")]
+pub use linux::{Smaps, SmapsRollup, MemoryBreakdown};
+
+#[cfg(target_os = "linux")]
+use linux::working_set_bytes_for_range;
+
+/// Returns how many bytes of the instance's linear memory are currently resident (backed by
+/// physical memory).
+///
+/// This is implemented on a per-OS basis (Linux via `/proc/self/smaps`, macOS via
+/// `mach_vm_region`, Windows via `QueryWorkingSetEx`) behind the common
+/// [`resident_bytes_for_range`] entry point, so the executor's memory-consumption tests are not
+/// restricted to Linux.
+pub fn instance_resident_bytes(instance: &dyn WasmInstance) -> usize {
+	let range = instance.linear_memory_range().expect("instance doesn't have linear memory?");
+	resident_bytes_for_range(range.start, range.end - range.start)
+		.expect("failed to get resident bytes")
+}
+
+/// Returns how many bytes of the instance's linear memory are touched (written to) while `f`
+/// runs, as opposed to the cumulative resident set measured by [`instance_resident_bytes`].
+///
+/// This relies on the kernel's soft-dirty page tracking (`/proc/self/clear_refs` and
+/// `/proc/self/pagemap`), so it is only available on Linux, and `None` is returned if the
+/// kernel was built without `CONFIG_MEM_SOFT_DIRTY`.
+#[cfg(target_os = "linux")]
+pub fn instance_working_set_bytes(instance: &dyn WasmInstance, f: impl FnOnce()) -> Option<usize> {
+	let range = instance.linear_memory_range().expect("instance doesn't have linear memory?");
+	working_set_bytes_for_range(range.start, range.end - range.start, f)
+}
@@ -0,0 +1,62 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Windows specific helper functions, based on `QueryWorkingSetEx`.
+
+use std::mem;
+use winapi::{
+	shared::minwindef::DWORD,
+	um::{
+		processthreadsapi::GetCurrentProcess,
+		psapi::{QueryWorkingSetEx, PSAPI_WORKING_SET_EX_INFORMATION},
+	},
+};
+
+/// Returns how many bytes of the given address range are currently resident (backed by phys
+/// mem), by asking `QueryWorkingSetEx` about every page in `[addr, addr + len)` and counting
+/// those whose `VirtualAttributes` has the "valid" bit set.
+pub(super) fn resident_bytes_for_range(addr: usize, len: usize) -> Option<usize> {
+	let page_size = page_size::get();
+	let page_count = (len + page_size - 1) / page_size;
+
+	let mut entries: Vec<PSAPI_WORKING_SET_EX_INFORMATION> = (0..page_count)
+		.map(|i| {
+			let mut entry: PSAPI_WORKING_SET_EX_INFORMATION = unsafe { mem::zeroed() };
+			entry.VirtualAddress = (addr + i * page_size) as *mut _;
+			entry
+		})
+		.collect();
+
+	let ok = unsafe {
+		QueryWorkingSetEx(
+			GetCurrentProcess(),
+			entries.as_mut_ptr() as *mut _,
+			(entries.len() * mem::size_of::<PSAPI_WORKING_SET_EX_INFORMATION>()) as DWORD,
+		)
+	};
+	if ok == 0 {
+		return None;
+	}
+
+	let resident_pages = entries
+		.iter()
+		.filter(|entry| unsafe { entry.VirtualAttributes.Valid() } != 0)
+		.count();
+
+	Some(resident_pages * page_size)
+}